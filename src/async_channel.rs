@@ -0,0 +1,175 @@
+use std::fmt;
+use std::marker::PhantomData;
+use async_trait::async_trait;
+use serde::{Serialize, Deserialize};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt, AsyncWriteExt};
+
+use super::{Value, SendError, RecvError};
+
+/// Async counterpart of `ChannelSend`, built on `AsyncChannel` instead of
+/// the blocking `Channel`.
+#[async_trait]
+pub trait AsyncChannelSend {
+    type Crr;
+    type Err;
+
+    async fn send(self, carrier: &mut Self::Crr) -> Result<(), Self::Err>;
+}
+
+/// Async counterpart of `ChannelRecv`.
+#[async_trait]
+pub trait AsyncChannelRecv: Sized {
+    type Crr;
+    type Err;
+
+    async fn recv(carrier: &mut Self::Crr) -> Result<Self, Self::Err>;
+}
+
+/// Async counterpart of `Carrier`: sends and receives a boolean branch
+/// decision over the wire. `session_types_ng`'s `Offer`/`Choose` drive only
+/// the sync `Carrier`, not this trait.
+#[async_trait]
+pub trait AsyncCarrier {
+    type SendChoiceErr;
+    async fn send_choice(&mut self, choice: bool) -> Result<(), Self::SendChoiceErr>;
+
+    type RecvChoiceErr;
+    async fn recv_choice(&mut self) -> Result<bool, Self::RecvChoiceErr>;
+}
+
+pub trait AsyncRWChannel: AsyncRead + AsyncWrite + Unpin + Send { }
+impl<T> AsyncRWChannel for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+/// An async carrier wrapping any `AsyncRead + AsyncWrite` transport (e.g.
+/// `tokio::net::TcpStream`), so session-typed protocols can run inside a
+/// tokio executor without blocking a thread per session.
+///
+/// Unlike `Channel`, framing is mandatory here: every `Value<T>` is first
+/// serialized into an intermediate buffer and then written as a
+/// length-prefixed frame, since there is no good way to deserialize
+/// incrementally from a non-blocking reader without an executor-friendly
+/// buffering scheme.
+pub struct AsyncChannel {
+    rw: Box<dyn AsyncRWChannel + 'static>,
+    max_frame_len: u32,
+}
+
+impl AsyncChannel {
+    pub fn new<C>(rw: C, max_frame_len: u32) -> AsyncChannel where C: AsyncRWChannel + 'static {
+        AsyncChannel {
+            rw: Box::new(rw),
+            max_frame_len: max_frame_len,
+        }
+    }
+}
+
+#[async_trait]
+impl<T> AsyncChannelSend for Value<T> where T: Serialize + Send {
+    type Crr = AsyncChannel;
+    type Err = SendError;
+
+    async fn send(self, carrier: &mut Self::Crr) -> Result<(), Self::Err> {
+        let value = self.get();
+        let mut encoded = Vec::new();
+        value.serialize(&mut rmp_serde::Serializer::new(&mut encoded))
+            .map_err(SendError::Encode)?;
+        carrier.rw.write_all(&(encoded.len() as u32).to_be_bytes()).await
+            .map_err(SendError::Flush)?;
+        carrier.rw.write_all(&encoded).await
+            .map_err(SendError::Flush)?;
+        carrier.rw.flush().await
+            .map_err(SendError::Flush)
+    }
+}
+
+#[async_trait]
+impl<T> AsyncChannelRecv for Value<T> where T: Deserialize + Send {
+    type Crr = AsyncChannel;
+    type Err = RecvError;
+
+    async fn recv(carrier: &mut Self::Crr) -> Result<Self, Self::Err> {
+        let mut len_buf = [0u8; 4];
+        carrier.rw.read_exact(&mut len_buf).await
+            .map_err(RecvError::Frame)?;
+        let len = u32::from_be_bytes(len_buf);
+        if len > carrier.max_frame_len {
+            return Err(RecvError::FrameTooLarge(len));
+        }
+        let mut body = vec![0u8; len as usize];
+        carrier.rw.read_exact(&mut body).await
+            .map_err(RecvError::Frame)?;
+        let value = Deserialize::deserialize(&mut rmp_serde::Deserializer::new(&body[..]))
+            .map_err(RecvError::Decode)?;
+        Ok(Value { value: value, _codec: PhantomData })
+    }
+}
+
+#[async_trait]
+impl AsyncCarrier for AsyncChannel {
+    type SendChoiceErr = SendError;
+    async fn send_choice(&mut self, choice: bool) -> Result<(), Self::SendChoiceErr> {
+        AsyncChannelSend::send(Value::new(choice), self).await
+    }
+
+    type RecvChoiceErr = RecvError;
+    async fn recv_choice(&mut self) -> Result<bool, Self::RecvChoiceErr> {
+        <Value<bool> as AsyncChannelRecv>::recv(self).await.map(Value::get)
+    }
+}
+
+/// Either half of an `async_client`/`async_server` exchange can fail on its
+/// own side of the wire; this just tags which one did.
+pub enum AsyncProtoError {
+    Send(SendError),
+    Recv(RecvError),
+}
+
+impl fmt::Debug for AsyncProtoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AsyncProtoError::Send(ref err) => f.debug_tuple("Send").field(err).finish(),
+            AsyncProtoError::Recv(ref err) => f.debug_tuple("Recv").field(err).finish(),
+        }
+    }
+}
+
+impl From<SendError> for AsyncProtoError {
+    fn from(err: SendError) -> AsyncProtoError {
+        AsyncProtoError::Send(err)
+    }
+}
+
+impl From<RecvError> for AsyncProtoError {
+    fn from(err: RecvError) -> AsyncProtoError {
+        AsyncProtoError::Recv(err)
+    }
+}
+
+/// Minimal async analogue of the sync `client`/`server` test helpers:
+/// `session_types_ng::Chan` only drives the sync `ChannelSend`/
+/// `ChannelRecv`/`Carrier` traits, so there is no session-typed way to run
+/// a protocol over `AsyncChannel`. These two functions drive the async
+/// traits directly instead -- a one-shot request/response exchange -- so
+/// at least that much of a protocol can genuinely run over the async
+/// carrier rather than leaving it unexercised.
+pub async fn async_server<Req, Resp, F>(carrier: &mut AsyncChannel, respond: F) -> Result<(), AsyncProtoError>
+    where
+        Req: Serialize + Deserialize + Send,
+        Resp: Serialize + Deserialize + Send,
+        F: FnOnce(Req) -> Resp + Send,
+{
+    let request = <Value<Req> as AsyncChannelRecv>::recv(carrier).await?;
+    let response = respond(request.get());
+    AsyncChannelSend::send(Value::new(response), carrier).await?;
+    Ok(())
+}
+
+pub async fn async_client<Req, Resp>(carrier: &mut AsyncChannel, request: Req) -> Result<Resp, AsyncProtoError>
+    where
+        Req: Serialize + Deserialize + Send,
+        Resp: Serialize + Deserialize + Send,
+{
+    AsyncChannelSend::send(Value::new(request), carrier).await?;
+    let response = <Value<Resp> as AsyncChannelRecv>::recv(carrier).await?;
+    Ok(response.get())
+}
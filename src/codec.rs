@@ -0,0 +1,61 @@
+use std::io::{Read, Write};
+use serde::{Serialize, Deserialize};
+
+/// Pluggable wire format for `Value<T>`. Lets callers swap MessagePack for
+/// a human-readable format (or anything else `serde` can drive) without
+/// touching the session-typed protocol definitions themselves.
+pub trait Codec {
+    type EncodeErr;
+    type DecodeErr;
+
+    fn encode<T, W>(value: &T, writer: W) -> Result<(), Self::EncodeErr>
+        where T: Serialize, W: Write;
+
+    fn decode<T, R>(reader: R) -> Result<T, Self::DecodeErr>
+        where T: Deserialize, R: Read;
+}
+
+/// The crate's original wire format and still the default: MessagePack via
+/// `rmp_serde`.
+#[derive(Clone, Copy, Debug)]
+pub struct RmpCodec;
+
+impl Codec for RmpCodec {
+    type EncodeErr = rmp_serde::encode::Error;
+    type DecodeErr = rmp_serde::decode::Error;
+
+    fn encode<T, W>(value: &T, writer: W) -> Result<(), Self::EncodeErr> where T: Serialize, W: Write {
+        value.serialize(&mut rmp_serde::Serializer::new(writer))
+    }
+
+    fn decode<T, R>(reader: R) -> Result<T, Self::DecodeErr> where T: Deserialize, R: Read {
+        Deserialize::deserialize(&mut rmp_serde::Deserializer::new(reader))
+    }
+}
+
+/// A human-readable alternative to `RmpCodec`, useful for debugging or
+/// interop with JSON-speaking peers.
+///
+/// Only safe to use with a `CodecChannel` configured via
+/// `with_max_frame_len`. `serde_json`'s reader-based deserializer peeks one
+/// byte past the end of a self-delimiting value (e.g. a bare number) to
+/// check whether it continues, and never pushes that byte back -- decoding
+/// straight off the shared carrier would silently eat the first byte of
+/// whatever comes next. In framed mode `decode` instead runs against an
+/// exact-length in-memory slice carved out by the length prefix, so there
+/// is nothing past the value's end for it to over-read.
+#[derive(Clone, Copy, Debug)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    type EncodeErr = serde_json::Error;
+    type DecodeErr = serde_json::Error;
+
+    fn encode<T, W>(value: &T, writer: W) -> Result<(), Self::EncodeErr> where T: Serialize, W: Write {
+        serde_json::to_writer(writer, value)
+    }
+
+    fn decode<T, R>(reader: R) -> Result<T, Self::DecodeErr> where T: Deserialize, R: Read {
+        serde_json::from_reader(reader)
+    }
+}
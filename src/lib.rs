@@ -1,94 +1,215 @@
 extern crate session_types_ng;
 extern crate serde;
 extern crate rmp_serde;
+extern crate serde_json;
+extern crate tokio;
+extern crate async_trait;
 
+use std::fmt;
 use std::io;
 use std::io::{Read, Write};
+use std::marker::PhantomData;
 use serde::{Serialize, Deserialize};
 use session_types_ng::{ChannelSend, ChannelRecv, Carrier};
 
-pub trait RWChannel : Read + Write { }
-impl<T> RWChannel for T where T: Read + Write {}
+mod stream;
+pub use stream::{Stream, StreamReader, DEFAULT_MAX_CHUNK_LEN};
 
-pub struct Channel {
+mod async_channel;
+pub use async_channel::{
+    AsyncChannel, AsyncChannelSend, AsyncChannelRecv, AsyncCarrier, AsyncRWChannel,
+    AsyncProtoError, async_client, async_server,
+};
+
+mod mux;
+pub use mux::{Multiplexer, MuxHandle, DEFAULT_MAX_FRAME_LEN as MUX_DEFAULT_MAX_FRAME_LEN};
+
+mod pipe;
+pub use pipe::{PipeEnd, PipeReader, PipeWriter};
+
+mod codec;
+pub use codec::{Codec, RmpCodec, JsonCodec};
+
+pub trait RWChannel : Read + Write + Send { }
+impl<T> RWChannel for T where T: Read + Write + Send {}
+
+/// A carrier generic over its wire format. `Channel` is a type alias for
+/// `CodecChannel<RmpCodec>`, the crate's original and default format, so
+/// existing code naming `Channel` keeps compiling unchanged.
+pub struct CodecChannel<C> {
     rw: Box<RWChannel + 'static>,
+    max_frame_len: Option<u32>,
+    _codec: PhantomData<C>,
 }
 
-impl Channel {
-    pub fn new<C>(rw: C) -> Channel where C: RWChannel + 'static {
-        Channel {
+/// The crate's original carrier, now a codec-specialized alias of
+/// `CodecChannel`.
+pub type Channel = CodecChannel<RmpCodec>;
+
+impl<C> CodecChannel<C> where C: Codec {
+    pub fn new<RW>(rw: RW) -> CodecChannel<C> where RW: RWChannel + 'static {
+        CodecChannel {
             rw: Box::new(rw),
+            max_frame_len: None,
+            _codec: PhantomData,
+        }
+    }
+
+    /// Enables framed mode: every `Value<T>` sent or received over this
+    /// channel is wrapped in an explicit `u32` byte-length prefix, giving
+    /// deterministic message boundaries and a hard ceiling on how much a
+    /// single `recv` will allocate.
+    pub fn with_max_frame_len<RW>(rw: RW, max_frame_len: u32) -> CodecChannel<C> where RW: RWChannel + 'static {
+        CodecChannel {
+            rw: Box::new(rw),
+            max_frame_len: Some(max_frame_len),
+            _codec: PhantomData,
         }
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct Value<T>(T);
+pub struct Value<T, C = RmpCodec> {
+    value: T,
+    _codec: PhantomData<C>,
+}
 
-impl<T> Value<T> where T: Serialize + Deserialize {
-    pub fn new(value: T) -> Value<T> {
-        Value(value)
+impl<T, C> Value<T, C> where T: Serialize + Deserialize {
+    pub fn new(value: T) -> Value<T, C> {
+        Value { value: value, _codec: PhantomData }
     }
 }
 
-impl<T> Value<T> {
+impl<T, C> Value<T, C> {
     pub fn get(self) -> T {
-        self.0
+        self.value
     }
 }
 
-#[derive(Debug)]
-pub enum SendError {
-    Encode(rmp_serde::encode::Error),
+impl<T: Clone, C> Clone for Value<T, C> {
+    fn clone(&self) -> Self {
+        Value { value: self.value.clone(), _codec: PhantomData }
+    }
+}
+
+impl<T: fmt::Debug, C> fmt::Debug for Value<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Value").field(&self.value).finish()
+    }
+}
+
+pub enum SendError<C: Codec = RmpCodec> {
+    Encode(C::EncodeErr),
     Flush(io::Error),
 }
 
-impl<T> ChannelSend for Value<T> where T: Serialize {
-    type Crr = Channel;
-    type Err = SendError;
+// Derived `Debug` would require `C: Debug`, which says nothing about
+// whether `C::EncodeErr` is `Debug` -- bound on the associated type
+// directly instead.
+impl<C: Codec> fmt::Debug for SendError<C> where C::EncodeErr: fmt::Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendError::Encode(ref err) => f.debug_tuple("Encode").field(err).finish(),
+            SendError::Flush(ref err) => f.debug_tuple("Flush").field(err).finish(),
+        }
+    }
+}
+
+impl<T, C> ChannelSend for Value<T, C> where T: Serialize, C: Codec {
+    type Crr = CodecChannel<C>;
+    type Err = SendError<C>;
 
     fn send(self, carrier: &mut Self::Crr) -> Result<(), Self::Err> {
-        self.0.serialize(&mut rmp_serde::Serializer::new(&mut carrier.rw))
-            .map_err(SendError::Encode)?;
+        match carrier.max_frame_len {
+            None =>
+                C::encode(&self.value, &mut carrier.rw)
+                    .map_err(SendError::Encode)?,
+            Some(..) => {
+                let mut encoded = Vec::new();
+                C::encode(&self.value, &mut encoded)
+                    .map_err(SendError::Encode)?;
+                carrier.rw.write_all(&(encoded.len() as u32).to_be_bytes())
+                    .map_err(SendError::Flush)?;
+                carrier.rw.write_all(&encoded)
+                    .map_err(SendError::Flush)?;
+            },
+        }
         carrier.rw.flush()
             .map_err(SendError::Flush)
     }
 }
 
-#[derive(Debug)]
-pub enum RecvError {
-    Decode(rmp_serde::decode::Error),
+pub enum RecvError<C: Codec = RmpCodec> {
+    Decode(C::DecodeErr),
+    /// The length prefix read in framed mode claimed a body larger than
+    /// the channel's configured `max_frame_len`, or a `Stream`'s
+    /// `max_chunk_len` header exceeded the receiver's ceiling.
+    FrameTooLarge(u32),
+    /// The length prefix itself (or a `Stream`'s `max_chunk_len` header),
+    /// or the framed body it announced, could not be read off the wire.
+    Frame(io::Error),
+}
+
+impl<C: Codec> fmt::Debug for RecvError<C> where C::DecodeErr: fmt::Debug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RecvError::Decode(ref err) => f.debug_tuple("Decode").field(err).finish(),
+            RecvError::FrameTooLarge(len) => f.debug_tuple("FrameTooLarge").field(&len).finish(),
+            RecvError::Frame(ref err) => f.debug_tuple("Frame").field(err).finish(),
+        }
+    }
 }
 
-impl<T> ChannelRecv for Value<T> where T: Deserialize {
-    type Crr = Channel;
-    type Err = RecvError;
+impl<T, C> ChannelRecv for Value<T, C> where T: Deserialize, C: Codec {
+    type Crr = CodecChannel<C>;
+    type Err = RecvError<C>;
 
     fn recv(carrier: &mut Self::Crr) -> Result<Self, Self::Err> {
-        let value = Deserialize::deserialize(&mut rmp_serde::Deserializer::new(&mut carrier.rw))
-            .map_err(RecvError::Decode)?;
-        Ok(Value(value))
+        let value = match carrier.max_frame_len {
+            None =>
+                C::decode(&mut carrier.rw)
+                    .map_err(RecvError::Decode)?,
+            Some(max_frame_len) => {
+                let mut len_buf = [0u8; 4];
+                carrier.rw.read_exact(&mut len_buf)
+                    .map_err(RecvError::Frame)?;
+                let len = u32::from_be_bytes(len_buf);
+                if len > max_frame_len {
+                    return Err(RecvError::FrameTooLarge(len));
+                }
+                let mut body = vec![0u8; len as usize];
+                carrier.rw.read_exact(&mut body)
+                    .map_err(RecvError::Frame)?;
+                C::decode(&body[..])
+                    .map_err(RecvError::Decode)?
+            },
+        };
+        Ok(Value { value: value, _codec: PhantomData })
     }
 }
 
-impl Carrier for Channel {
-    type SendChoiceErr = SendError;
+impl<C> Carrier for CodecChannel<C> where C: Codec {
+    type SendChoiceErr = SendError<C>;
     fn send_choice(&mut self, choice: bool) -> Result<(), Self::SendChoiceErr> {
-        Value(choice).send(self)
+        ChannelSend::send(Value::<bool, C>::new(choice), self)
     }
 
-    type RecvChoiceErr = RecvError;
+    type RecvChoiceErr = RecvError<C>;
     fn recv_choice(&mut self) -> Result<bool, Self::RecvChoiceErr> {
-        Value::recv(self).map(|Value(value)| value)
+        <Value<bool, C> as ChannelRecv>::recv(self).map(Value::get)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::net;
+    use std::io::{Read, Write};
+    use std::thread;
     use std::thread::spawn;
+    use std::time::Duration;
     use session_types_ng::{Chan, Rec, Send, Recv, Choose, Offer, More, Nil, End, Var, Z, HasDual};
-    use super::{Channel, Value};
+    use session_types_ng::{ChannelSend, ChannelRecv};
+    use super::{Channel, CodecChannel, Codec, JsonCodec, Value, RecvError, Stream, Multiplexer, PipeEnd};
+    use super::{AsyncChannel, async_client, async_server};
 
     // Server initial prompt: either start value searching session or force quit.
     type Proto =
@@ -245,4 +366,203 @@ mod tests {
             .second().unwrap()
             .close();
     }
+
+    #[test]
+    fn pipe_comm() {
+        // Same protocol session exchange as `tcp_comm`, but over the
+        // in-process carrier pair so the test doesn't need to bind a port.
+        let (master_carrier, slave_carrier) = Channel::pipe();
+        let _th = spawn(move || {
+            let mut carrier = slave_carrier;
+            loop {
+                let (next_carrier, shutdown) = server(Chan::new(carrier));
+                if shutdown {
+                    break;
+                } else {
+                    carrier = next_carrier;
+                }
+            }
+        });
+
+        let (carrier, maybe_pos) =
+            client(Chan::new(master_carrier), 3, [-1, 0, 1, 2, 3, 4].iter().cloned());
+        assert_eq!(maybe_pos, Some(4));
+        let (carrier, maybe_pos) =
+            client(Chan::new(carrier), -2, [-1, 0, 1, 2, 3, 4].iter().cloned());
+        assert_eq!(maybe_pos, None);
+
+        Chan::<_, (), CliProto>::new(carrier)
+            .second().unwrap()
+            .close();
+    }
+
+    #[test]
+    fn json_codec_roundtrip() {
+        // `JsonCodec` is a drop-in alternative to the default `RmpCodec`;
+        // check its `encode`/`decode` pair round-trips a value.
+        let mut buf = Vec::new();
+        JsonCodec::encode(&42isize, &mut buf).unwrap();
+        let value: isize = JsonCodec::decode(&buf[..]).unwrap();
+        assert_eq!(value, 42);
+    }
+
+    #[test]
+    fn json_codec_framed_sequential_roundtrip() {
+        // `JsonCodec::decode` reads off a generic `Read` one byte at a time,
+        // with a one-byte, never-pushed-back lookahead used to tell whether
+        // a number keeps going -- see the doc comment on `JsonCodec`. In
+        // framed mode each `recv` decodes from an exact-length in-memory
+        // slice instead, so that lookahead byte can't leak into the next
+        // message. Prove two JSON-coded values round-trip in sequence over
+        // one reused, framed carrier.
+        let (left, right) = PipeEnd::pair();
+        let mut sender = CodecChannel::<JsonCodec>::with_max_frame_len(left, 1024);
+        let mut receiver = CodecChannel::<JsonCodec>::with_max_frame_len(right, 1024);
+
+        let _th = spawn(move || {
+            Value::<isize, JsonCodec>::new(42).send(&mut sender).unwrap();
+            Value::<isize, JsonCodec>::new(7).send(&mut sender).unwrap();
+        });
+
+        let first = Value::<isize, JsonCodec>::recv(&mut receiver).unwrap();
+        let second = Value::<isize, JsonCodec>::recv(&mut receiver).unwrap();
+        assert_eq!(first.get(), 42);
+        assert_eq!(second.get(), 7);
+        _th.join().unwrap();
+    }
+
+    #[test]
+    fn framed_roundtrip() {
+        let (left, right) = PipeEnd::pair();
+        let mut sender = Channel::with_max_frame_len(left, 1024);
+        let mut receiver = Channel::with_max_frame_len(right, 1024);
+        Value::new(42isize).send(&mut sender).unwrap();
+        let value = Value::<isize>::recv(&mut receiver).unwrap();
+        assert_eq!(value.get(), 42);
+    }
+
+    #[test]
+    fn framed_too_large() {
+        // The sender's frame easily fits its own (generous) limit, but the
+        // receiver's limit is far smaller, so `recv` must reject the frame
+        // by its length prefix alone, without attempting to decode it.
+        let (left, right) = PipeEnd::pair();
+        let mut sender = Channel::with_max_frame_len(left, 1024);
+        let mut receiver = Channel::with_max_frame_len(right, 4);
+        Value::new(String::from("far larger than four bytes")).send(&mut sender).unwrap();
+        match Value::<String>::recv(&mut receiver) {
+            Err(RecvError::FrameTooLarge(..)) => {},
+            other => panic!("expected FrameTooLarge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn stream_roundtrip_with_configured_ceiling() {
+        // Pick a `max_chunk_len` bigger than `DEFAULT_MAX_CHUNK_LEN` and a
+        // payload that needs more than one chunk at that size, so this only
+        // passes if `recv` actually honors the sender's chosen chunk size.
+        // The receiver must opt into that larger ceiling explicitly via
+        // `with_max_frame_len` -- see `stream_chunk_too_large_rejected` for
+        // what happens without it.
+        let max_chunk_len = 2 * super::DEFAULT_MAX_CHUNK_LEN;
+        let payload: Vec<u8> = (0u8 .. 255).cycle().take(3 * max_chunk_len as usize).collect();
+        let expected = payload.clone();
+
+        let (left, right) = PipeEnd::pair();
+        let mut sender = Channel::new(left);
+        let mut receiver = Channel::with_max_frame_len(right, max_chunk_len);
+
+        let _th = spawn(move || {
+            Stream::with_max_chunk_len(payload, max_chunk_len).send(&mut sender).unwrap();
+        });
+
+        let received = Stream::<Vec<u8>>::recv(&mut receiver).unwrap();
+        assert_eq!(received.get(), expected);
+        _th.join().unwrap();
+    }
+
+    #[test]
+    fn stream_chunk_too_large_rejected() {
+        // Without a configured ceiling, `recv` must not trust a wire header
+        // claiming a larger `max_chunk_len` than `DEFAULT_MAX_CHUNK_LEN` --
+        // honoring it unconditionally would let a hostile peer force an
+        // arbitrarily large allocation via the length it then claims for a
+        // single chunk.
+        let max_chunk_len = 2 * super::DEFAULT_MAX_CHUNK_LEN;
+
+        let (left, right) = PipeEnd::pair();
+        let mut sender = Channel::new(left);
+        let mut receiver = Channel::new(right);
+
+        let _th = spawn(move || {
+            // The peer may never notice the rejection (the receiver stops
+            // reading after the header), so ignore a broken-pipe error here.
+            let _ = Stream::with_max_chunk_len(vec![0u8; 4], max_chunk_len).send(&mut sender);
+        });
+
+        match Stream::<Vec<u8>>::recv(&mut receiver) {
+            Err(RecvError::FrameTooLarge(len)) => assert_eq!(len, max_chunk_len),
+            other => panic!("expected FrameTooLarge, got {:?}", other),
+        }
+        let _ = _th.join();
+    }
+
+    #[test]
+    fn mux_write_not_blocked_by_pending_read() {
+        // Regression test for a deadlock where a stream blocked in `read`
+        // held a lock that another stream's `write`/`flush` also needed.
+        let (left, right) = PipeEnd::pair();
+        let (left_reader, left_writer) = left.split();
+        let (right_reader, right_writer) = right.split();
+
+        let mut left_mux = Multiplexer::new(left_reader, left_writer);
+        let mut right_mux = Multiplexer::new(right_reader, right_writer);
+
+        let left_a = left_mux.open_stream(0);
+        let mut right_a = right_mux.open_stream(0);
+        let mut left_b = left_mux.open_stream(0);
+        let mut right_b = right_mux.open_stream(0);
+
+        // `left_a` blocks waiting for data nobody has sent yet; in the
+        // pre-fix design this held the whole `Multiplexer`'s lock, so
+        // `left_b`'s write below would never get a chance to run.
+        let blocked_reader = spawn(move || {
+            let mut left_a = left_a;
+            let mut buf = [0u8; 5];
+            left_a.read_exact(&mut buf).unwrap();
+            buf
+        });
+
+        thread::sleep(Duration::from_millis(50));
+
+        left_b.write_all(b"world").unwrap();
+        left_b.flush().unwrap();
+        let mut buf = [0u8; 5];
+        right_b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"world");
+
+        // Now unblock the reader thread.
+        right_a.write_all(b"hello").unwrap();
+        right_a.flush().unwrap();
+        assert_eq!(&blocked_reader.join().unwrap(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn async_request_response() {
+        // Drives a real request/response exchange over `AsyncChannel`
+        // using `async_client`/`async_server`, proving the async carrier
+        // and traits actually move a protocol end to end rather than
+        // sitting unused.
+        let (client_io, server_io) = tokio::io::duplex(1024);
+        let mut client_carrier = AsyncChannel::new(client_io, 1024);
+        let mut server_carrier = AsyncChannel::new(server_io, 1024);
+
+        let server_th = tokio::spawn(async move {
+            async_server::<isize, isize, _>(&mut server_carrier, |req| req * 2).await.unwrap();
+        });
+
+        let response = async_client::<isize, isize>(&mut client_carrier, 21).await.unwrap();
+        assert_eq!(response, 42);
+        server_th.await.unwrap();
+    }
 }
@@ -0,0 +1,294 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque, BTreeMap};
+use std::io;
+use std::io::{Read, Write};
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+
+/// Largest payload carried by a single multiplexed frame; larger writes
+/// are split across several frames so a bulk transfer on one stream still
+/// yields the wire to other streams between frames.
+pub const DEFAULT_MAX_FRAME_LEN: u32 = 16 * 1024;
+
+fn io_failed(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, msg.to_string())
+}
+
+struct ReadState {
+    inbound: HashMap<u16, VecDeque<u8>>,
+    error: Option<String>,
+}
+
+// Owns the frames read off the wire, independently of `WriteSide`, so a
+// stream blocked here waiting for inbound data can never hold up another
+// stream's write -- the two sides share no lock.
+struct ReadSide {
+    state: Mutex<ReadState>,
+    ready: Condvar,
+}
+
+impl ReadSide {
+    fn new() -> ReadSide {
+        ReadSide {
+            state: Mutex::new(ReadState { inbound: HashMap::new(), error: None }),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn fill_until(&self, stream_id: u16) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.inbound.get(&stream_id).map_or(false, |q| !q.is_empty()) {
+                return Ok(());
+            }
+            if let Some(ref msg) = state.error {
+                return Err(io_failed(msg));
+            }
+            state = self.ready.wait(state).unwrap();
+        }
+    }
+
+    fn push(&self, stream_id: u16, payload: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.inbound.entry(stream_id).or_insert_with(VecDeque::new).extend(payload);
+        self.ready.notify_all();
+    }
+
+    fn fail(&self, err: &io::Error) {
+        let mut state = self.state.lock().unwrap();
+        state.error = Some(err.to_string());
+        self.ready.notify_all();
+    }
+}
+
+struct WriteState {
+    // frames queued for a stream but not yet written to the wire
+    pending: HashMap<u16, VecDeque<Vec<u8>>>,
+    // per-priority round robin rotation of stream ids with pending frames
+    rotation: BTreeMap<u8, VecDeque<u16>>,
+    error: Option<String>,
+}
+
+// Owns the frames still waiting to go out. The background writer thread
+// drains this independently of whatever `ReadSide` is doing, so a stream
+// blocked in a read never freezes another stream's `write`/`flush`.
+struct WriteSide {
+    state: Mutex<WriteState>,
+    has_work: Condvar,
+    drained: Condvar,
+}
+
+impl WriteSide {
+    fn new() -> WriteSide {
+        WriteSide {
+            state: Mutex::new(WriteState { pending: HashMap::new(), rotation: BTreeMap::new(), error: None }),
+            has_work: Condvar::new(),
+            drained: Condvar::new(),
+        }
+    }
+
+    fn enqueue(&self, stream_id: u16, priority: u8, chunk: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        let was_empty = state.pending.get(&stream_id).map_or(true, |q| q.is_empty());
+        state.pending.entry(stream_id).or_insert_with(VecDeque::new).push_back(chunk);
+        if was_empty {
+            state.rotation.entry(priority).or_insert_with(VecDeque::new).push_back(stream_id);
+        }
+        self.has_work.notify_all();
+    }
+
+    // blocks until a stream's queue is empty (used by `flush`)
+    fn wait_drained(&self, stream_id: u16) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.pending.get(&stream_id).map_or(true, |q| q.is_empty()) {
+                return match state.error {
+                    Some(ref msg) => Err(io_failed(msg)),
+                    None => Ok(()),
+                };
+            }
+            state = self.drained.wait(state).unwrap();
+        }
+    }
+
+    // blocks until there is a frame to write (serving the highest priority
+    // rotation to exhaustion before a lower one gets a turn, and rotating
+    // equal-priority streams one frame at a time so a large transfer on one
+    // of them can't starve the others), or returns `None` once failed.
+    fn wait_next(&self) -> Option<(u16, u8, Vec<u8>)> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some(top_priority) = state.rotation.keys().next_back().cloned() {
+                let stream_id = {
+                    let queue = state.rotation.get_mut(&top_priority).unwrap();
+                    match queue.pop_front() {
+                        Some(id) => id,
+                        None => {
+                            state.rotation.remove(&top_priority);
+                            continue;
+                        },
+                    }
+                };
+                let chunk = state.pending.get_mut(&stream_id).and_then(|q| q.pop_front());
+                let has_more = state.pending.get(&stream_id).map_or(false, |q| !q.is_empty());
+                if has_more {
+                    state.rotation.entry(top_priority).or_insert_with(VecDeque::new).push_back(stream_id);
+                }
+                if state.rotation.get(&top_priority).map_or(false, |q| q.is_empty()) {
+                    state.rotation.remove(&top_priority);
+                }
+                if let Some(chunk) = chunk {
+                    return Some((stream_id, top_priority, chunk));
+                } else {
+                    continue;
+                }
+            }
+            if state.error.is_some() {
+                return None;
+            }
+            state = self.has_work.wait(state).unwrap();
+        }
+    }
+
+    fn fail(&self, err: &io::Error) {
+        let mut state = self.state.lock().unwrap();
+        state.error = Some(err.to_string());
+        self.drained.notify_all();
+        self.has_work.notify_all();
+    }
+}
+
+fn read_frame<R>(mut rw: R) -> io::Result<(u16, Vec<u8>)> where R: Read {
+    let mut id_buf = [0u8; 2];
+    rw.read_exact(&mut id_buf)?;
+    let stream_id = u16::from_be_bytes(id_buf);
+    let mut prio_buf = [0u8; 1];
+    rw.read_exact(&mut prio_buf)?;
+    let mut len_buf = [0u8; 4];
+    rw.read_exact(&mut len_buf)?;
+    let len = u32::from_be_bytes(len_buf);
+    let mut payload = vec![0u8; len as usize];
+    rw.read_exact(&mut payload)?;
+    Ok((stream_id, payload))
+}
+
+fn write_frame<W>(mut rw: W, stream_id: u16, priority: u8, chunk: &[u8]) -> io::Result<()> where W: Write {
+    rw.write_all(&stream_id.to_be_bytes())?;
+    rw.write_all(&[priority])?;
+    rw.write_all(&(chunk.len() as u32).to_be_bytes())?;
+    rw.write_all(chunk)
+}
+
+/// Owns one underlying duplex carrier and hands out multiple independent
+/// `Channel`-like handles (`MuxHandle`), each tagged with a `u16
+/// stream_id`, so several session-typed conversations can interleave over
+/// a single connection instead of requiring one connection per session.
+pub struct Multiplexer {
+    read_side: Arc<ReadSide>,
+    write_side: Arc<WriteSide>,
+    next_stream_id: u16,
+}
+
+impl Multiplexer {
+    /// Takes an already-split reader and writer (e.g. a `TcpStream` and its
+    /// `try_clone`, or `PipeEnd::split`) and hands each to its own
+    /// background thread. Splitting up front, rather than sharing one
+    /// handle behind a lock, is what lets a `MuxHandle::read` blocked
+    /// waiting on inbound data run fully independently of another
+    /// `MuxHandle::write`/`flush`.
+    pub fn new<R, W>(reader: R, writer: W) -> Multiplexer
+        where R: Read + Send + 'static, W: Write + Send + 'static
+    {
+        let read_side = Arc::new(ReadSide::new());
+        let write_side = Arc::new(WriteSide::new());
+
+        let reader_read_side = read_side.clone();
+        thread::spawn(move || {
+            let mut reader = reader;
+            loop {
+                match read_frame(&mut reader) {
+                    Ok((stream_id, payload)) => reader_read_side.push(stream_id, payload),
+                    Err(err) => {
+                        reader_read_side.fail(&err);
+                        return;
+                    },
+                }
+            }
+        });
+
+        let writer_write_side = write_side.clone();
+        thread::spawn(move || {
+            let mut writer = writer;
+            while let Some((stream_id, priority, chunk)) = writer_write_side.wait_next() {
+                let result = write_frame(&mut writer, stream_id, priority, &chunk)
+                    .and_then(|()| writer.flush());
+                match result {
+                    Ok(()) => writer_write_side.drained.notify_all(),
+                    Err(err) => {
+                        writer_write_side.fail(&err);
+                        return;
+                    },
+                }
+            }
+        });
+
+        Multiplexer {
+            read_side: read_side,
+            write_side: write_side,
+            next_stream_id: 0,
+        }
+    }
+
+    /// Opens a new logical stream with the given priority (higher values
+    /// are served first). The returned handle implements `Read + Write`
+    /// and can be wrapped with `Channel::new` like any other carrier.
+    pub fn open_stream(&mut self, priority: u8) -> MuxHandle {
+        let stream_id = self.next_stream_id;
+        self.next_stream_id += 1;
+        MuxHandle {
+            stream_id: stream_id,
+            priority: priority,
+            read_side: self.read_side.clone(),
+            write_side: self.write_side.clone(),
+        }
+    }
+}
+
+/// One logical, independently readable/writable stream multiplexed over a
+/// `Multiplexer`'s shared carrier.
+pub struct MuxHandle {
+    stream_id: u16,
+    priority: u8,
+    read_side: Arc<ReadSide>,
+    write_side: Arc<WriteSide>,
+}
+
+impl Read for MuxHandle {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.read_side.fill_until(self.stream_id)?;
+        let mut state = self.read_side.state.lock().unwrap();
+        let queue = state.inbound.get_mut(&self.stream_id).unwrap();
+        let n = cmp::min(buf.len(), queue.len());
+        for (i, byte) in queue.drain(.. n).enumerate() {
+            buf[i] = byte;
+        }
+        Ok(n)
+    }
+}
+
+impl Write for MuxHandle {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let max_chunk_len = DEFAULT_MAX_FRAME_LEN as usize;
+        let mut offset = 0;
+        while offset < buf.len() {
+            let end = cmp::min(offset + max_chunk_len, buf.len());
+            self.write_side.enqueue(self.stream_id, self.priority, buf[offset .. end].to_vec());
+            offset = end;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_side.wait_drained(self.stream_id)
+    }
+}
@@ -0,0 +1,158 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::io;
+use std::io::{Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex, Condvar};
+
+use super::Channel;
+
+/// A byte queue pushed onto the right and drained from the left, modeled
+/// on netapp's `BytesBuf`.
+#[derive(Default)]
+struct BytesBuf {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+/// One direction of an in-memory pipe: a queue plus a condvar so a
+/// blocking `read` can wait for the peer to `write` rather than busy-loop.
+struct Pipe {
+    buf: Mutex<BytesBuf>,
+    ready: Condvar,
+}
+
+impl Pipe {
+    fn new() -> Pipe {
+        Pipe {
+            buf: Mutex::new(BytesBuf::default()),
+            ready: Condvar::new(),
+        }
+    }
+
+    fn push(&self, data: &[u8]) {
+        let mut buf = self.buf.lock().unwrap();
+        buf.bytes.extend(data.iter().cloned());
+        self.ready.notify_all();
+    }
+
+    fn close(&self) {
+        let mut buf = self.buf.lock().unwrap();
+        buf.closed = true;
+        self.ready.notify_all();
+    }
+
+    fn drain(&self, out: &mut [u8]) -> io::Result<usize> {
+        let mut buf = self.buf.lock().unwrap();
+        while buf.bytes.is_empty() && !buf.closed {
+            buf = self.ready.wait(buf).unwrap();
+        }
+        let n = cmp::min(out.len(), buf.bytes.len());
+        for (i, byte) in buf.bytes.drain(.. n).enumerate() {
+            out[i] = byte;
+        }
+        Ok(n)
+    }
+}
+
+/// One end of an in-memory carrier pair created by `Channel::pipe`: writes
+/// feed the peer's reads through a shared `Arc`-ed queue, with no socket
+/// involved.
+pub struct PipeEnd {
+    outbound: Arc<Pipe>,
+    inbound: Arc<Pipe>,
+}
+
+impl Read for PipeEnd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inbound.drain(buf)
+    }
+}
+
+impl Write for PipeEnd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.push(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeEnd {
+    fn drop(&mut self) {
+        // wake up a peer blocked in `read` so it observes end-of-stream
+        // instead of hanging forever once this end goes away.
+        self.outbound.close();
+    }
+}
+
+impl PipeEnd {
+    /// Builds a connected pair of pipe ends whose writes feed each other's
+    /// reads, useful directly (e.g. as a `Multiplexer` carrier after
+    /// `split`) or wrapped with `Channel::new`/`Channel::pipe`.
+    pub fn pair() -> (PipeEnd, PipeEnd) {
+        let left_to_right = Arc::new(Pipe::new());
+        let right_to_left = Arc::new(Pipe::new());
+        let left = PipeEnd { outbound: left_to_right.clone(), inbound: right_to_left.clone() };
+        let right = PipeEnd { outbound: right_to_left, inbound: left_to_right };
+        (left, right)
+    }
+
+    /// Splits this end into independent reader and writer halves backed by
+    /// the same underlying queues, so a caller (e.g. `Multiplexer`) can hand
+    /// reading and writing to separate threads and avoid one direction
+    /// blocking the other.
+    pub fn split(self) -> (PipeReader, PipeWriter) {
+        let inbound = self.inbound.clone();
+        let outbound = self.outbound.clone();
+        mem::forget(self);
+        (PipeReader { inbound: inbound }, PipeWriter { outbound: outbound })
+    }
+}
+
+/// The read half of a split `PipeEnd`.
+pub struct PipeReader {
+    inbound: Arc<Pipe>,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inbound.drain(buf)
+    }
+}
+
+/// The write half of a split `PipeEnd`.
+pub struct PipeWriter {
+    outbound: Arc<Pipe>,
+}
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.outbound.push(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        // same end-of-stream signal `PipeEnd::drop` sends, now owned by the
+        // writer half since only it can meaningfully say "no more data".
+        self.outbound.close();
+    }
+}
+
+impl Channel {
+    /// Builds a connected pair of in-memory carriers whose writes feed
+    /// each other's reads, so session-typed protocol tests can run fully
+    /// in-process and deterministically, without binding a port.
+    pub fn pipe() -> (Channel, Channel) {
+        let (left, right) = PipeEnd::pair();
+        (Channel::new(left), Channel::new(right))
+    }
+}
@@ -0,0 +1,219 @@
+use std::cmp;
+use std::io;
+use std::io::{Read, Write};
+use serde::{Serialize, Deserialize};
+use session_types_ng::{ChannelSend, ChannelRecv};
+
+use super::{Channel, SendError, RecvError};
+
+/// Default ceiling on a single chunk's payload size, in bytes. Chosen to
+/// match the size netapp settled on after tracking down a bug that
+/// truncated packets larger than 16k.
+///
+/// This also doubles as the receiver's ceiling on the wire-supplied
+/// `max_chunk_len` header when the carrier has no `max_frame_len`
+/// configured (see `ChannelRecv for Stream::recv`): a header claiming a
+/// larger chunk size is rejected rather than trusted, since honoring it
+/// unconditionally would let a peer force an arbitrarily large allocation.
+pub const DEFAULT_MAX_CHUNK_LEN: u32 = 16 * 1024;
+
+const FLAG_CONTINUE: u8 = 1;
+const FLAG_END_OF_STREAM: u8 = 0;
+
+/// A message type that transmits a value as a sequence of length-prefixed
+/// chunks rather than a single MessagePack blob, so large payloads don't
+/// have to be fully buffered and encoded in memory before anything goes
+/// on the wire.
+///
+/// On the wire this is a `[u32 max_chunk_len]` header, declaring the
+/// ceiling the sender chunked to, followed by the chunk frames themselves:
+/// `[u8 continuation_flag][u32 chunk_len][chunk_bytes]`, where
+/// `continuation_flag = 1` means more frames follow and `0` marks
+/// end-of-stream (an empty final frame is allowed). The header lets
+/// `recv` size its guard to whatever `max_chunk_len` the sender actually
+/// used, rather than assuming `DEFAULT_MAX_CHUNK_LEN` -- but only up to
+/// the receiver's own ceiling; see `DEFAULT_MAX_CHUNK_LEN`.
+#[derive(Clone, Debug)]
+pub struct Stream<T> {
+    value: T,
+    max_chunk_len: u32,
+}
+
+impl<T> Stream<T> {
+    pub fn new(value: T) -> Stream<T> {
+        Stream::with_max_chunk_len(value, DEFAULT_MAX_CHUNK_LEN)
+    }
+
+    pub fn with_max_chunk_len(value: T, max_chunk_len: u32) -> Stream<T> {
+        Stream { value: value, max_chunk_len: max_chunk_len }
+    }
+
+    pub fn get(self) -> T {
+        self.value
+    }
+}
+
+fn write_chunk<W>(mut rw: W, flag: u8, chunk: &[u8]) -> io::Result<()> where W: Write {
+    rw.write_all(&[flag])?;
+    rw.write_all(&(chunk.len() as u32).to_be_bytes())?;
+    rw.write_all(chunk)
+}
+
+/// Buffers serialized bytes up to `max_chunk_len` and flushes each full
+/// buffer as a wire chunk frame, so `rmp_serde::Serializer` can write
+/// straight into the chunker instead of `Stream::send` having to encode
+/// the whole value into one `Vec` up front.
+struct ChunkWriter<'a, W: 'a> {
+    rw: &'a mut W,
+    max_chunk_len: usize,
+    buf: Vec<u8>,
+}
+
+impl<'a, W> ChunkWriter<'a, W> where W: Write {
+    fn new(rw: &'a mut W, max_chunk_len: u32) -> ChunkWriter<'a, W> {
+        ChunkWriter { rw: rw, max_chunk_len: cmp::max(max_chunk_len as usize, 1), buf: Vec::new() }
+    }
+
+    fn flush_chunk(&mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            write_chunk(&mut *self.rw, FLAG_CONTINUE, &self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.flush_chunk()?;
+        write_chunk(&mut *self.rw, FLAG_END_OF_STREAM, &[])
+    }
+}
+
+impl<'a, W> Write for ChunkWriter<'a, W> where W: Write {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut offset = 0;
+        while offset < buf.len() {
+            let space = self.max_chunk_len - self.buf.len();
+            let take = cmp::min(space, buf.len() - offset);
+            self.buf.extend_from_slice(&buf[offset .. offset + take]);
+            offset += take;
+            if self.buf.len() == self.max_chunk_len {
+                self.flush_chunk()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T> ChannelSend for Stream<T> where T: Serialize {
+    type Crr = Channel;
+    type Err = SendError;
+
+    fn send(self, carrier: &mut Self::Crr) -> Result<(), Self::Err> {
+        carrier.rw.write_all(&self.max_chunk_len.to_be_bytes())
+            .map_err(SendError::Flush)?;
+
+        {
+            let mut writer = ChunkWriter::new(&mut carrier.rw, self.max_chunk_len);
+            self.value.serialize(&mut rmp_serde::Serializer::new(&mut writer))
+                .map_err(SendError::Encode)?;
+            writer.finish().map_err(SendError::Flush)?;
+        }
+        carrier.rw.flush().map_err(SendError::Flush)
+    }
+}
+
+impl<T> ChannelRecv for Stream<T> where T: Deserialize {
+    type Crr = Channel;
+    type Err = RecvError;
+
+    fn recv(carrier: &mut Self::Crr) -> Result<Self, Self::Err> {
+        let mut max_chunk_len_buf = [0u8; 4];
+        carrier.rw.read_exact(&mut max_chunk_len_buf)
+            .map_err(RecvError::Frame)?;
+        let max_chunk_len = u32::from_be_bytes(max_chunk_len_buf);
+
+        // Never trust the wire header past what this receiver is willing
+        // to allocate for a single chunk: a carrier configured with
+        // `with_max_frame_len` raises the ceiling to that value, otherwise
+        // it stays at `DEFAULT_MAX_CHUNK_LEN`.
+        let ceiling = carrier.max_frame_len.unwrap_or(DEFAULT_MAX_CHUNK_LEN);
+        if max_chunk_len > ceiling {
+            return Err(RecvError::FrameTooLarge(max_chunk_len));
+        }
+
+        let value = {
+            let mut reader = StreamReader::with_max_chunk_len(carrier, max_chunk_len);
+            Deserialize::deserialize(&mut rmp_serde::Deserializer::new(&mut reader))
+                .map_err(RecvError::Decode)?
+        };
+        Ok(Stream::with_max_chunk_len(value, max_chunk_len))
+    }
+}
+
+/// Lazily pulls the chunk frames written by `Stream::send` and presents
+/// them as a single contiguous byte stream, so a payload can be consumed
+/// (deserialized or otherwise) without buffering it all up front.
+pub struct StreamReader<'a> {
+    carrier: &'a mut Channel,
+    max_chunk_len: u32,
+    current: Vec<u8>,
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> StreamReader<'a> {
+    pub fn new(carrier: &'a mut Channel) -> StreamReader<'a> {
+        StreamReader::with_max_chunk_len(carrier, DEFAULT_MAX_CHUNK_LEN)
+    }
+
+    pub fn with_max_chunk_len(carrier: &'a mut Channel, max_chunk_len: u32) -> StreamReader<'a> {
+        StreamReader {
+            carrier: carrier,
+            max_chunk_len: max_chunk_len,
+            current: Vec::new(),
+            pos: 0,
+            done: false,
+        }
+    }
+
+    fn pull_chunk(&mut self) -> io::Result<()> {
+        let mut flag_buf = [0u8; 1];
+        self.carrier.rw.read_exact(&mut flag_buf)?;
+        let mut len_buf = [0u8; 4];
+        self.carrier.rw.read_exact(&mut len_buf)?;
+        let len = u32::from_be_bytes(len_buf);
+        if len > self.max_chunk_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream chunk length exceeds max_chunk_len",
+            ));
+        }
+        let mut chunk = vec![0u8; len as usize];
+        self.carrier.rw.read_exact(&mut chunk)?;
+        self.current = chunk;
+        self.pos = 0;
+        self.done = flag_buf[0] == FLAG_END_OF_STREAM;
+        Ok(())
+    }
+}
+
+impl<'a> Read for StreamReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.pos < self.current.len() {
+                let n = cmp::min(buf.len(), self.current.len() - self.pos);
+                buf[.. n].copy_from_slice(&self.current[self.pos .. self.pos + n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            self.pull_chunk()?;
+        }
+    }
+}